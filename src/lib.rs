@@ -11,97 +11,262 @@ extern crate pbc_lib;
 use pbc_contract_common::address::Address;
 use pbc_contract_common::context::ContractContext;
 use pbc_contract_common::events::EventGroup;
+use pbc_contract_common::shortname::Shortname;
 use pbc_contract_common::zk::{CalculationStatus, SecretVarId, ZkClosed, ZkInputDef, ZkState, ZkStateChange};
 use read_write_rpc_derive::ReadWriteRPC;
 use read_write_state_derive::ReadWriteState;
 
-/// Secret variable metadata. Unused for this contract, so we use a zero-sized struct to save space.
+mod zk_compute;
+
+/// Identifies one hosted game within this contract's registry.
+type GameId = u64;
+
+/// Secret variable metadata: which player the variable belongs to, and which hosted game
+/// it's scoped to (a single contract instance can host many concurrent games).
 #[derive(ReadWriteState, ReadWriteRPC, Debug)]
 struct SecretVarMetadata {
     player: bool,
+    game_id: GameId,
+}
+
+/// The phase a game is in.
+#[derive(ReadWriteState, ReadWriteRPC, Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u8)]
+enum Phase {
+    /// Waiting for a second player to join.
+    Lobby = 0,
+    /// Waiting for both players to submit their boards.
+    Setup = 1,
+    /// At least one board has been fully submitted and sent for board-validity checking; the
+    /// other player may still be submitting. Stays here until both boards are proven legal.
+    Validating = 2,
+    /// Both boards are proven legal; players are taking turns shooting.
+    Playing = 3,
+    /// One player has sunk the other's entire fleet.
+    Ended = 4,
+}
+
+/// The outcome of a single resolved shot, carried by `ShotResolved` events.
+///
+/// There is no `Sunk` variant: telling a sink apart from an ordinary hit would require
+/// tracking which cells belong to which ship on-chain, which would reveal ship shapes and
+/// defeat the point of keeping boards secret. Sink detection is descoped for now.
+#[derive(ReadWriteRPC, Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u8)]
+enum ShotOutcome {
+    Miss = 0,
+    Hit = 1,
+    /// The shot that brought the target's hit count up to `EXPECTED_FLEET_CELLS`.
+    Win = 2,
 }
 
+/// Shortname of the `game_started_event` no-op action, used purely as an event tag.
+const SHORTNAME_GAME_STARTED_EVENT: u32 = 0x02;
+
+/// Shortname of the `shot_resolved_event` no-op action, used purely as an event tag.
+const SHORTNAME_SHOT_RESOLVED_EVENT: u32 = 0x03;
+
+/// Shortname of the `game_ended_event` no-op action, used purely as an event tag.
+const SHORTNAME_GAME_ENDED_EVENT: u32 = 0x04;
+
+/// Shortname of the `board_rejected_event` no-op action, used purely as an event tag.
+const SHORTNAME_BOARD_REJECTED_EVENT: u32 = 0x07;
+
 /// The maximum size of MPC variables.
 const BITLENGTH_OF_SECRET_VARIABLES: u32 = 32;
 
+/// The side length of the (square) Battleship board.
+const BOARD_SIDE: u32 = 10;
+
+/// The number of cells on the board.
+const BOARD_CELLS: u32 = BOARD_SIDE * BOARD_SIDE;
+
+/// The number of secret words needed to pack `BOARD_CELLS` bits into
+/// `BITLENGTH_OF_SECRET_VARIABLES`-sized secret variables.
+const BOARD_WORDS: u32 = (BOARD_CELLS + BITLENGTH_OF_SECRET_VARIABLES - 1) / BITLENGTH_OF_SECRET_VARIABLES;
+
+/// The number of occupied cells in a legal classic fleet (ships of length 5, 4, 3, 3, 2).
+const EXPECTED_FLEET_CELLS: u32 = 17;
+
+/// A single hosted match, from lobby to game over.
 #[derive(ReadWriteRPC, Debug)]
-#[state]
-struct ContractState {
+struct Game {
+    id: GameId,
     player_a: Address,
-    player_b: Address,
+    /// Before `join_game` is called this is `None` (open to anyone) or `Some(invited)`
+    /// (reserved for that address). Afterwards it holds whichever address actually joined.
+    player_b: Option<Address>,
     next_turn: Address,
     winner: Option<Address>,
-    hit_a: Option<bool>,
-    hit_b: Option<bool>,
-    game_state: String,
+    game_state: Phase,
+    /// Whether player A's board has been proven to be a legal fleet. `None` until the
+    /// board-validity computation for player A has resolved.
+    board_a_valid: Option<bool>,
+    /// Whether player B's board has been proven to be a legal fleet. `None` until the
+    /// board-validity computation for player B has resolved.
+    board_b_valid: Option<bool>,
+    /// Which of player A's cells have been hit so far, indexed the same way as `shoot`'s
+    /// `idx = y * BOARD_SIDE + x`.
+    hits_against_a: Vec<bool>,
+    /// Which of player B's cells have been hit so far, indexed the same way as `shoot`'s
+    /// `idx = y * BOARD_SIDE + x`.
+    hits_against_b: Vec<bool>,
+    /// The cell index of the shot currently being resolved by the MPC, if any.
+    pending_shot_idx: Option<u32>,
+}
+
+/// A registry of every game hosted by this contract instance.
+#[derive(ReadWriteRPC, Debug)]
+#[state]
+struct ContractState {
+    games: Vec<Game>,
+    next_game_id: GameId,
+    /// Board-validity computations that are ready to start but couldn't be, because the zk
+    /// engine (which only ever runs one computation at a time across the whole contract) was
+    /// already busy with another game's computation. Drained as soon as the engine frees up.
+    validation_queue: Vec<(GameId, bool)>,
 }
 
 /// INIT
 #[init (zk = true)]
-fn initialize(ctx: ContractContext, zk_state: ZkState<SecretVarMetadata>, player_a: Address, player_b: Address) -> ContractState {
+fn initialize(ctx: ContractContext, zk_state: ZkState<SecretVarMetadata>) -> ContractState {
     ContractState {
-        player_a,
-        player_b,
-        next_turn: player_a,
+        games: vec![],
+        next_game_id: 0,
+        validation_queue: vec![],
+    }
+}
+
+/// Opens a new game slot with the caller as player A. If `invited_player_b` is `Some`, only
+/// that address may `join_game`; otherwise anyone may take the second seat.
+#[action(shortname = 0x05)]
+fn create_game(
+    context: ContractContext,
+    mut state: ContractState,
+    invited_player_b: Option<Address>,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    let id = state.next_game_id;
+    state.next_game_id += 1;
+
+    state.games.push(Game {
+        id,
+        player_a: context.sender,
+        player_b: invited_player_b,
+        next_turn: context.sender,
         winner: None,
-        hit_a: None,
-        hit_b: None,
-        game_state: "Setup".to_string(),
+        game_state: Phase::Lobby,
+        board_a_valid: None,
+        board_b_valid: None,
+        hits_against_a: vec![false; BOARD_CELLS as usize],
+        hits_against_b: vec![false; BOARD_CELLS as usize],
+        pending_shot_idx: None,
+    });
+
+    (state, vec![], vec![])
+}
+
+/// Fills the second seat of a lobby, starting the board-setup phase.
+#[action(shortname = 0x06)]
+fn join_game(
+    context: ContractContext,
+    mut state: ContractState,
+    game_id: GameId,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    let game = find_game_mut(&mut state, game_id);
+    assert_eq!(game.game_state, Phase::Lobby, "Game is not open for joining");
+    assert_ne!(context.sender, game.player_a, "Cannot play against yourself");
+    if let Some(invited) = game.player_b {
+        assert_eq!(context.sender, invited, "This game is reserved for another player");
     }
+
+    game.player_b = Some(context.sender);
+    game.game_state = Phase::Setup;
+
+    (state, vec![], vec![])
 }
 
+/// Finds the game with the given id, panicking if it does not exist.
+fn find_game(state: &ContractState, game_id: GameId) -> &Game {
+    state
+        .games
+        .iter()
+        .find(|game| game.id == game_id)
+        .unwrap_or_else(|| panic!("No game with id {}", game_id))
+}
+
+/// Finds the game with the given id, panicking if it does not exist.
+fn find_game_mut(state: &mut ContractState, game_id: GameId) -> &mut Game {
+    state
+        .games
+        .iter_mut()
+        .find(|game| game.id == game_id)
+        .unwrap_or_else(|| panic!("No game with id {}", game_id))
+}
 
 #[zk_on_secret_input(shortname = 0x40)]
 fn setup_board(
     context: ContractContext,
     state: ContractState,
     zk_state: ZkState<SecretVarMetadata>,
+    game_id: GameId,
 ) -> (
     ContractState,
     Vec<EventGroup>,
     ZkInputDef<SecretVarMetadata>,
 ) {
-    let player_id = get_player_id(context.sender, &state);
+    let game = find_game(&state, game_id);
+    // Player A's board can be fully inputted (moving the game to `Validating`) before player B
+    // has submitted anything, so both phases must be accepted here — otherwise B can never
+    // get their board in and the game deadlocks.
+    assert!(
+        game.game_state == Phase::Setup || game.game_state == Phase::Validating,
+        "Game is not accepting boards"
+    );
+    let player_id = get_player_id(context.sender, game);
 
     let input_def = ZkInputDef {
         seal: false,
-        metadata: SecretVarMetadata { player: player_id },
-        expected_bit_lengths: vec![BITLENGTH_OF_SECRET_VARIABLES],
+        metadata: SecretVarMetadata { player: player_id, game_id },
+        expected_bit_lengths: vec![BITLENGTH_OF_SECRET_VARIABLES; BOARD_WORDS as usize],
     };
     (state, vec![], input_def)
 }
 
-fn get_player_id(sender: Address, state: &ContractState) -> bool {
-    if sender == state.player_a {
+fn get_player_id(sender: Address, game: &Game) -> bool {
+    if sender == game.player_a {
         false
-    } else if sender == state.player_b {
+    } else if Some(sender) == game.player_b {
         true
     } else {
         panic!("{:?} is not a player", sender);
     }
 }
 
-fn get_player_address(id: bool, state: &ContractState) -> Address {
+fn get_player_address(id: bool, game: &Game) -> Address {
     if id {
-        state.player_b
+        game.player_b.expect("Game has no second player yet")
     } else {
-        state.player_a
+        game.player_a
     }
 }
 
 #[action(shortname = 0x01, zk = true)]
 fn shoot(
     context: ContractContext,
-    state: ContractState,
+    mut state: ContractState,
     zk_state: ZkState<SecretVarMetadata>,
-    position: u32,
+    game_id: GameId,
+    x: u32,
+    y: u32,
 ) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    let game = find_game_mut(&mut state, game_id);
+
     assert_eq!(
-        context.sender, state.next_turn,
+        context.sender, game.next_turn,
         "Its not your turn"
     );
 
-    assert_eq!(state.game_state, "Playing".to_string(), "Game is not ready to be played");
+    assert_eq!(game.game_state, Phase::Playing, "Game is not ready to be played");
 
     assert_eq!(
         zk_state.calculation_state,
@@ -110,22 +275,146 @@ fn shoot(
         zk_state.calculation_state,
     );
 
-    let player_id = get_player_id(context.sender, &state);
+    assert!(x < BOARD_SIDE && y < BOARD_SIDE, "Shot is out of bounds");
+    let idx = y * BOARD_SIDE + x;
+    assert!(idx < BOARD_CELLS, "Shot is out of bounds");
+
+    game.pending_shot_idx = Some(idx);
+
+    let player_id = get_player_id(context.sender, game);
     let output_variable_metadata: Vec<SecretVarMetadata> = vec![
         SecretVarMetadata {
             player: !player_id,
+            game_id,
         }
     ];
     (
         state,
         vec![],
-        vec![ZkStateChange::start_computation_with_inputs(output_variable_metadata, vec![
+        vec![zk_compute::zk_compute_start(
+            output_variable_metadata,
             !player_id,
-            position,
-        ])],
+            idx,
+            game_id,
+        )],
     )
 }
 
+/// No-op action that exists purely to carry a `GameStarted` event: off-chain indexers can
+/// follow a match by watching for interactions with this shortname instead of diffing state.
+/// Only the contract itself may invoke this, so an external caller can't inject a forged event.
+#[action(shortname = 0x02)]
+fn game_started_event(
+    context: ContractContext,
+    state: ContractState,
+    game_id: GameId,
+    player_a: Address,
+    player_b: Address,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    assert_eq!(context.sender, context.contract_address, "This event may only be emitted by the contract itself");
+    (state, vec![], vec![])
+}
+
+/// No-op action that exists purely to carry a `ShotResolved` event. Only the contract itself
+/// may invoke this, so an external caller can't inject a forged event.
+#[action(shortname = 0x03)]
+fn shot_resolved_event(
+    context: ContractContext,
+    state: ContractState,
+    game_id: GameId,
+    shooter: Address,
+    target: Address,
+    x: u32,
+    y: u32,
+    outcome: ShotOutcome,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    assert_eq!(context.sender, context.contract_address, "This event may only be emitted by the contract itself");
+    (state, vec![], vec![])
+}
+
+/// No-op action that exists purely to carry a `GameEnded` event. Only the contract itself may
+/// invoke this, so an external caller can't inject a forged event.
+#[action(shortname = 0x04)]
+fn game_ended_event(
+    context: ContractContext,
+    state: ContractState,
+    game_id: GameId,
+    winner: Address,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    assert_eq!(context.sender, context.contract_address, "This event may only be emitted by the contract itself");
+    (state, vec![], vec![])
+}
+
+/// No-op action that exists purely to carry a `BoardRejected` event. Only the contract itself
+/// may invoke this, so an external caller can't inject a forged event.
+#[action(shortname = 0x07)]
+fn board_rejected_event(
+    context: ContractContext,
+    state: ContractState,
+    game_id: GameId,
+    player: Address,
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    assert_eq!(context.sender, context.contract_address, "This event may only be emitted by the contract itself");
+    (state, vec![], vec![])
+}
+
+/// Builds an event carrying a `GameStarted` notification.
+fn build_game_started_event(self_address: Address, game_id: GameId, player_a: Address, player_b: Address) -> EventGroup {
+    let mut builder = EventGroup::builder();
+    builder
+        .call(self_address, Shortname::from_u32(SHORTNAME_GAME_STARTED_EVENT))
+        .argument(game_id)
+        .argument(player_a)
+        .argument(player_b)
+        .done();
+    builder.build()
+}
+
+/// Builds an event carrying a `ShotResolved` notification.
+fn build_shot_resolved_event(
+    self_address: Address,
+    game_id: GameId,
+    shooter: Address,
+    target: Address,
+    x: u32,
+    y: u32,
+    outcome: ShotOutcome,
+) -> EventGroup {
+    let mut builder = EventGroup::builder();
+    builder
+        .call(self_address, Shortname::from_u32(SHORTNAME_SHOT_RESOLVED_EVENT))
+        .argument(game_id)
+        .argument(shooter)
+        .argument(target)
+        .argument(x)
+        .argument(y)
+        .argument(outcome)
+        .done();
+    builder.build()
+}
+
+/// Builds an event carrying a `GameEnded` notification.
+fn build_game_ended_event(self_address: Address, game_id: GameId, winner: Address) -> EventGroup {
+    let mut builder = EventGroup::builder();
+    builder
+        .call(self_address, Shortname::from_u32(SHORTNAME_GAME_ENDED_EVENT))
+        .argument(game_id)
+        .argument(winner)
+        .done();
+    builder.build()
+}
+
+/// Builds an event carrying a `BoardRejected` notification.
+fn build_board_rejected_event(self_address: Address, game_id: GameId, player: Address) -> EventGroup {
+    let mut builder = EventGroup::builder();
+    builder
+        .call(self_address, Shortname::from_u32(SHORTNAME_BOARD_REJECTED_EVENT))
+        .argument(game_id)
+        .argument(player)
+        .done();
+    builder.build()
+}
+
 #[zk_on_compute_complete]
 fn auction_compute_complete(
     ontext: ContractContext,
@@ -155,38 +444,103 @@ fn open_auction_variable(
         "Unexpected number of output variables"
     );
 
-    let was_ship = read_variable_u32_le(&zk_state, opened_variables.get(0));
+    let opened_value = read_variable_u32_le(&zk_state, opened_variables.get(0));
     let x: &ZkClosed<SecretVarMetadata> = zk_state.get_variable(opened_variables.get(0).unwrap().clone()).unwrap();
-    let shot_at = get_player_address(x.metadata.player, &state);
+    let target_player_id = x.metadata.player;
+    let game_id = x.metadata.game_id;
 
-    if shot_at == state.player_a {
-        state.hit_a = Some(was_ship != 0);
-    } else {
-        state.hit_b = Some(was_ship != 0);
-    }
+    let mut rejected_board_player = None;
+    let events;
+    {
+        let game = find_game_mut(&mut state, game_id);
+        let player_address = get_player_address(target_player_id, game);
 
-    if state.hit_a.is_some() && state.hit_b.is_some() {
-        state.game_state = "ENDED".to_string();
-        state.winner = calculate_winner(&state);
-    }
+        if game.game_state == Phase::Validating {
+            let is_valid = opened_value != 0;
 
-    state.next_turn = shot_at;
+            if player_address == game.player_a {
+                game.board_a_valid = Some(is_valid);
+            } else {
+                game.board_b_valid = Some(is_valid);
+            }
 
-    (state, vec![], vec![ZkStateChange::OutputComplete { variables_to_delete: vec![] }])
-}
+            let mut validating_events = vec![];
+            if !is_valid {
+                // Clear the rejected board's words so the word count in `inputted_variable`
+                // starts back at zero and a resubmission re-triggers validation cleanly,
+                // instead of stacking on top of the old words and never matching the fleet size.
+                rejected_board_player = Some(target_player_id);
+                validating_events.push(build_board_rejected_event(context.contract_address, game_id, player_address));
+            } else if game.board_a_valid == Some(true) && game.board_b_valid == Some(true) {
+                game.game_state = Phase::Playing;
+                validating_events.push(build_game_started_event(
+                    context.contract_address,
+                    game_id,
+                    game.player_a,
+                    game.player_b.unwrap(),
+                ));
+            }
+            events = validating_events;
+        } else {
+            let was_hit = opened_value != 0;
+            let shot_at = player_address;
+            let shooter = get_player_address(!target_player_id, game);
+            let idx = game.pending_shot_idx.take().expect("No shot is pending resolution") as usize;
+            let shot_x = (idx as u32) % BOARD_SIDE;
+            let shot_y = (idx as u32) / BOARD_SIDE;
 
-fn calculate_winner(state: &ContractState) -> Option<Address> {
-    let hit_a = state.hit_a.unwrap();
-    let hit_b = state.hit_b.unwrap();
-    if hit_a && hit_b {
-        None
-    } else if hit_a {
-        Some(state.player_b)
-    } else if hit_b {
-        Some(state.player_a)
-    } else {
-        None
+            let hits = if shot_at == game.player_a {
+                &mut game.hits_against_a
+            } else {
+                &mut game.hits_against_b
+            };
+            hits[idx] = was_hit;
+            let total_hits = hits.iter().filter(|hit| **hit).count() as u32;
+
+            let mut shot_events = vec![];
+            if total_hits == EXPECTED_FLEET_CELLS {
+                game.game_state = Phase::Ended;
+                game.winner = Some(shooter);
+
+                shot_events.push(build_shot_resolved_event(
+                    context.contract_address, game_id, shooter, shot_at, shot_x, shot_y, ShotOutcome::Win,
+                ));
+                shot_events.push(build_game_ended_event(context.contract_address, game_id, shooter));
+            } else {
+                let outcome = if was_hit { ShotOutcome::Hit } else { ShotOutcome::Miss };
+                shot_events.push(build_shot_resolved_event(
+                    context.contract_address, game_id, shooter, shot_at, shot_x, shot_y, outcome,
+                ));
+            }
+
+            game.next_turn = shot_at;
+            events = shot_events;
+        }
     }
+
+    // Each opened variable is a single throwaway bit (a hit/miss or a validity flag); once read
+    // it has no further use, so reclaim it instead of letting `secret_variables` grow forever.
+    let mut variables_to_delete = opened_variables;
+    if let Some(rejected_player) = rejected_board_player {
+        variables_to_delete.extend(
+            zk_state
+                .secret_variables
+                .iter()
+                .filter(|variable| variable.metadata.player == rejected_player && variable.metadata.game_id == game_id)
+                .map(|variable| variable.id),
+        );
+    }
+    let mut zk_state_changes = vec![ZkStateChange::OutputComplete { variables_to_delete }];
+    if let Some((queued_game_id, queued_player_id)) = state.validation_queue.pop() {
+        let output_variable_metadata = vec![SecretVarMetadata { player: queued_player_id, game_id: queued_game_id }];
+        zk_state_changes.push(zk_compute::zk_compute_valid_board_start(
+            output_variable_metadata,
+            queued_player_id,
+            queued_game_id,
+        ));
+    }
+
+    (state, events, zk_state_changes)
 }
 
 #[zk_on_variable_inputted]
@@ -195,12 +549,43 @@ fn inputted_variable(
     mut state: ContractState,
     zk_state: ZkState<SecretVarMetadata>,
     inputted_variable: SecretVarId,
-) -> ContractState {
-    let amount_of_boards = zk_state.secret_variables.len() as u32;
-    if amount_of_boards == 2 {
-        state.game_state = "Playing".to_string();
+) -> (ContractState, Vec<EventGroup>, Vec<ZkStateChange>) {
+    let x: &ZkClosed<SecretVarMetadata> = zk_state.get_variable(inputted_variable).unwrap();
+    let player_id = x.metadata.player;
+    let game_id = x.metadata.game_id;
+
+    // A board is complete once all of its `BOARD_WORDS` words have been inputted for this
+    // player in this game. Kick off the board-validity computation right away, without
+    // waiting for the other player.
+    let words_inputted_for_player = zk_state
+        .secret_variables
+        .iter()
+        .filter(|variable| variable.metadata.game_id == game_id && variable.metadata.player == player_id)
+        .count() as u32;
+
+    let mut zk_state_changes = vec![];
+    if words_inputted_for_player == BOARD_WORDS {
+        {
+            let game = find_game_mut(&mut state, game_id);
+            game.game_state = Phase::Validating;
+        }
+
+        // The zk engine only ever runs one computation at a time across the whole contract.
+        // If it's already busy validating another game's board, queue this one instead of
+        // issuing a second `start_computation` that the engine would have to drop.
+        if zk_state.calculation_state == CalculationStatus::Waiting {
+            let output_variable_metadata = vec![SecretVarMetadata { player: player_id, game_id }];
+            zk_state_changes.push(zk_compute::zk_compute_valid_board_start(
+                output_variable_metadata,
+                player_id,
+                game_id,
+            ));
+        } else {
+            state.validation_queue.push((game_id, player_id));
+        }
     }
-    state
+
+    (state, vec![], zk_state_changes)
 }
 
 
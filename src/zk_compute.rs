@@ -1,17 +1,187 @@
-/// Template zk computation. Computes the sum of the secret variables.
+/// Template zk computation. Computes whether the targeted cell of a player's board is a hit,
+/// and whether a player's board is a legal Battleship fleet.
+use pbc_contract_codegen::zk_compute;
 use pbc_zk::*;
 
-pub fn zk_compute(target: bool, position: u32) -> Sbi32 {
-    let guess: Sbi32 = if position != 0 {
-        Sbi32::from(1)
-    } else {
-        Sbi32::from(0)
-    };
+/// Computes whether cell `idx` of `target`'s board (in game `game_id`) is occupied by a ship.
+///
+/// The board is secret-shared as several 32-bit words. We pick out the word that
+/// contains bit `idx` (`idx / 32`) and extract that bit (`idx % 32`) without revealing
+/// any of the other cells.
+#[zk_compute(shortname = 0x61)]
+pub fn zk_compute(target: bool, idx: u32, game_id: u64) -> Sbi32 {
+    let word_index = idx / BITLENGTH_OF_SECRET_VARIABLES;
+    let bit_index = idx % BITLENGTH_OF_SECRET_VARIABLES;
+
+    let mut word_counter: u32 = 0;
     for variable_id in secret_variable_ids() {
-        if load_metadata::<bool>(variable_id) == target {
-            let ship_placed = load_sbi::<Sbi32>(variable_id);
-            return (ship_placed == guess) as Sbi32;
+        let metadata = load_metadata::<SecretVarMetadata>(variable_id);
+        if metadata.player == target && metadata.game_id == game_id {
+            if word_counter == word_index {
+                let cell_word = load_sbi::<Sbi32>(variable_id);
+                return (cell_word >> bit_index) & Sbi32::from(1);
+            }
+            word_counter += 1;
         }
     }
     Sbi32::from(0)
-}
\ No newline at end of file
+}
+
+/// Computes whether `player`'s board (in game `game_id`) is a legal Battleship fleet, without
+/// revealing any cell.
+///
+/// Three secret invariants are checked and ANDed together:
+/// * the total number of occupied cells equals `EXPECTED_FLEET_CELLS`;
+/// * no occupied cell has both a horizontally- and a vertically-occupied neighbour, which
+///   rules out bent, T-shaped, or cross-shaped placements — every legal ship is a straight
+///   1-cell-wide run, so a cell that branches in both directions can never belong to one;
+/// * counting each straight run exactly once (from its leftmost/topmost cell) yields exactly
+///   one run of length 5, one of length 4, two of length 3 and one of length 2 — the classic
+///   fleet. Runs of any other length (including isolated single cells, which is what a
+///   diagonal "staircase" placement decomposes into once the bend check above is applied)
+///   are not counted here; the extra cells they hold make the cell-count check fail.
+#[zk_compute(shortname = 0x62)]
+pub fn zk_compute_valid_board(player: bool, game_id: u64) -> Sbi32 {
+    let mut words: Vec<Sbi32> = Vec::with_capacity(BOARD_WORDS as usize);
+    for variable_id in secret_variable_ids() {
+        let metadata = load_metadata::<SecretVarMetadata>(variable_id);
+        if metadata.player == player && metadata.game_id == game_id {
+            words.push(load_sbi::<Sbi32>(variable_id));
+        }
+    }
+
+    // Count only the `BOARD_CELLS` real cells, not the padding bits in the last word (the
+    // board doesn't divide evenly into `BITLENGTH_OF_SECRET_VARIABLES`-sized words) — otherwise
+    // this would disagree with the fleet-shape loop below about what counts as "occupied".
+    let mut popcount = Sbi32::from(0);
+    for idx in 0..BOARD_CELLS {
+        popcount = popcount + board_cell(&words, idx);
+    }
+    let popcount_ok = (popcount == Sbi32::from(EXPECTED_FLEET_CELLS as i32)) as Sbi32;
+
+    let mut no_bend = Sbi32::from(1);
+    let mut len5 = Sbi32::from(0);
+    let mut len4 = Sbi32::from(0);
+    let mut len3 = Sbi32::from(0);
+    let mut len2 = Sbi32::from(0);
+
+    for idx in 0..BOARD_CELLS {
+        let occupied = board_cell(&words, idx);
+        let left = left_occupied(&words, idx);
+        let right = right_occupied(&words, idx);
+        let up = up_occupied(&words, idx);
+        let down = down_occupied(&words, idx);
+
+        let has_h_neighbor = (left | right) & Sbi32::from(1);
+        let has_v_neighbor = (up | down) & Sbi32::from(1);
+        no_bend = no_bend & (Sbi32::from(1) - (has_h_neighbor & has_v_neighbor));
+
+        let is_start = occupied & (Sbi32::from(1) - left) & (Sbi32::from(1) - up);
+        let run_length = is_start
+            * (horizontal_run_length(&words, idx) + vertical_run_length(&words, idx) - Sbi32::from(1));
+
+        len5 = len5 + ((run_length == Sbi32::from(5)) as Sbi32);
+        len4 = len4 + ((run_length == Sbi32::from(4)) as Sbi32);
+        len3 = len3 + ((run_length == Sbi32::from(3)) as Sbi32);
+        len2 = len2 + ((run_length == Sbi32::from(2)) as Sbi32);
+    }
+
+    let fleet_shape_ok = (len5 == Sbi32::from(1))
+        & (len4 == Sbi32::from(1))
+        & (len3 == Sbi32::from(2))
+        & (len2 == Sbi32::from(1));
+
+    popcount_ok & no_bend & fleet_shape_ok
+}
+
+/// Extracts bit `idx` of the board packed across `words`.
+fn board_cell(words: &[Sbi32], idx: u32) -> Sbi32 {
+    (words[(idx / BITLENGTH_OF_SECRET_VARIABLES) as usize] >> (idx % BITLENGTH_OF_SECRET_VARIABLES)) & Sbi32::from(1)
+}
+
+/// Whether the cell to the left of `idx` (same row) is occupied; `0` on the left edge.
+fn left_occupied(words: &[Sbi32], idx: u32) -> Sbi32 {
+    if idx % BOARD_SIDE == 0 {
+        Sbi32::from(0)
+    } else {
+        board_cell(words, idx - 1)
+    }
+}
+
+/// Whether the cell to the right of `idx` (same row) is occupied; `0` on the right edge.
+fn right_occupied(words: &[Sbi32], idx: u32) -> Sbi32 {
+    if idx % BOARD_SIDE == BOARD_SIDE - 1 {
+        Sbi32::from(0)
+    } else {
+        board_cell(words, idx + 1)
+    }
+}
+
+/// Whether the cell above `idx` (same column) is occupied; `0` on the top edge.
+fn up_occupied(words: &[Sbi32], idx: u32) -> Sbi32 {
+    if idx < BOARD_SIDE {
+        Sbi32::from(0)
+    } else {
+        board_cell(words, idx - BOARD_SIDE)
+    }
+}
+
+/// Whether the cell below `idx` (same column) is occupied; `0` on the bottom edge.
+fn down_occupied(words: &[Sbi32], idx: u32) -> Sbi32 {
+    if idx + BOARD_SIDE >= BOARD_CELLS {
+        Sbi32::from(0)
+    } else {
+        board_cell(words, idx + BOARD_SIDE)
+    }
+}
+
+/// Length of the contiguous occupied run starting at `idx` and extending rightward along its
+/// row, counting `idx` itself. `0` if `idx` is unoccupied.
+fn horizontal_run_length(words: &[Sbi32], idx: u32) -> Sbi32 {
+    let row_start = idx - (idx % BOARD_SIDE);
+    let row_end = row_start + BOARD_SIDE;
+    let mut chain = Sbi32::from(1);
+    let mut length = Sbi32::from(0);
+    let mut cursor = idx;
+    while cursor < row_end {
+        chain = chain & board_cell(words, cursor);
+        length = length + chain;
+        cursor += 1;
+    }
+    length
+}
+
+/// Length of the contiguous occupied run starting at `idx` and extending downward along its
+/// column, counting `idx` itself. `0` if `idx` is unoccupied.
+fn vertical_run_length(words: &[Sbi32], idx: u32) -> Sbi32 {
+    let mut chain = Sbi32::from(1);
+    let mut length = Sbi32::from(0);
+    let mut cursor = idx;
+    while cursor < BOARD_CELLS {
+        chain = chain & board_cell(words, cursor);
+        length = length + chain;
+        cursor += BOARD_SIDE;
+    }
+    length
+}
+
+/// Secret variable metadata. Mirrors the struct of the same name in `lib.rs`.
+struct SecretVarMetadata {
+    player: bool,
+    game_id: u64,
+}
+
+/// The maximum size of MPC variables. Mirrors the constant of the same name in `lib.rs`.
+const BITLENGTH_OF_SECRET_VARIABLES: u32 = 32;
+
+/// The side length of the (square) Battleship board. Mirrors the constant in `lib.rs`.
+const BOARD_SIDE: u32 = 10;
+
+/// The number of cells on the board. Mirrors the constant in `lib.rs`.
+const BOARD_CELLS: u32 = BOARD_SIDE * BOARD_SIDE;
+
+/// The number of secret words the board is packed into. Mirrors the constant in `lib.rs`.
+const BOARD_WORDS: u32 = (BOARD_CELLS + BITLENGTH_OF_SECRET_VARIABLES - 1) / BITLENGTH_OF_SECRET_VARIABLES;
+
+/// The number of occupied cells in a legal classic fleet. Mirrors the constant in `lib.rs`.
+const EXPECTED_FLEET_CELLS: u32 = 17;